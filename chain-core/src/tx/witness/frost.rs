@@ -0,0 +1,623 @@
+//! FROST (Flexible Round-Optimized Schnorr Threshold signatures) for `TreeSig`.
+//!
+//! Before this module, t-of-n spending meant enumerating every `C(n, t)`
+//! combination of MuSig-combined keys as separate Merkle leaves (see the old
+//! `get_2_of_3_tx_witness` test helper, which built `pkc1`/`pkc2`/`pkc3`).
+//! That blows up combinatorially and requires an all-signers interactive
+//! session per leaf. FROST instead produces a single aggregate group key and
+//! a single Schnorr signature from any quorum of `t` participants, which
+//! still verifies under the unchanged `TreeSig(PublicKey, SchnorrSignature,
+//! ops)` path -- it only changes how the key and signature are produced.
+//!
+//! To actually pass the existing `schnorr_verify` call unmodified, the
+//! aggregate key and signature have to match whatever convention that
+//! (opaque, vendored) function recomputes internally. `schnorr_verify`
+//! takes a full `PublicKey`, not an x-only type, which is the pre-BIP340
+//! "zkp draft" Schnorr convention rather than BIP340 itself: the challenge
+//! hashes the 33-byte compressed public key (not just its x-coordinate),
+//! and a point is normalized by testing whether its y-coordinate is a
+//! quadratic residue mod the field prime (not simply even) -- see
+//! [`is_quadratic_residue`]. [`keygen_round2`] normalizes the group public
+//! key (and every participant's secret share) this way, and [`group_nonce`]/
+//! [`partial_sign`]/[`aggregate_signature`] do the same for the nonce,
+//! negating the nonce contribution (never the challenge term) whenever it's
+//! needed. [`challenge`] hashes with a from-scratch SHA-256 ([`sha256`])
+//! rather than this chain's own `txid_hash`, since `txid_hash` commits to
+//! transaction ids elsewhere in this crate and has no reason to agree with
+//! a Schnorr library's challenge hash.
+
+use std::collections::BTreeMap;
+
+use secp256k1::{
+    key::{PublicKey, SecretKey},
+    schnorrsig::SchnorrSignature,
+    Message, Secp256k1, Signing, Verification,
+};
+
+use crate::tx::data::txid_hash;
+
+/// order of the secp256k1 group, big-endian, used for the scalar arithmetic
+/// behind Lagrange interpolation (participant indices are tiny, but the
+/// coefficients themselves live in the scalar field)
+const ORDER: [u8; 32] = [
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+    0xfe, 0xba, 0xae, 0xdc, 0xe6, 0xaf, 0x48, 0xa0, 0x3b, 0xbf, 0xd2, 0x5e, 0x8c, 0xd0, 0x36, 0x41,
+];
+const ORDER_MINUS_TWO: [u8; 32] = [
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+    0xfe, 0xba, 0xae, 0xdc, 0xe6, 0xaf, 0x48, 0xa0, 0x3b, 0xbf, 0xd2, 0x5e, 0x8c, 0xd0, 0x36, 0x3f,
+];
+const ONE: [u8; 32] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1,
+];
+
+/// secp256k1 base *field* prime `p = 2^256 - 2^32 - 977`, used only by
+/// [`is_quadratic_residue`] below -- not to be confused with [`ORDER`] (the
+/// group/scalar order `n`) that every other scalar helper in this file uses.
+/// `SecretKey`'s own arithmetic is mod `n`, so a mod-`p` check needs its own
+/// from-scratch modular exponentiation.
+const FIELD_PRIME: [u8; 32] = [
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xfe, 0xff, 0xff, 0xfc, 0x2f,
+];
+/// `(FIELD_PRIME - 1) / 2`, the Euler's-criterion exponent
+const FIELD_PRIME_HALF: [u8; 32] = [
+    0x7f, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f, 0xff, 0xfe, 0x17,
+];
+
+/// 1-indexed participant identifier (`i` in the FROST paper)
+pub type ParticipantIndex = u16;
+
+fn u64_to_be32(v: u64) -> [u8; 32] {
+    let mut out = [0u8; 32];
+    out[24..32].copy_from_slice(&v.to_be_bytes());
+    out
+}
+
+/// `a - b`, assuming `a >= b` (true here: `ORDER` vastly exceeds any
+/// participant-index difference)
+fn sub32(a: [u8; 32], b: [u8; 32]) -> [u8; 32] {
+    let mut out = [0u8; 32];
+    let mut borrow = 0i16;
+    for i in (0..32).rev() {
+        let mut diff = i16::from(a[i]) - i16::from(b[i]) - borrow;
+        if diff < 0 {
+            diff += 256;
+            borrow = 1;
+        } else {
+            borrow = 0;
+        }
+        out[i] = diff as u8;
+    }
+    out
+}
+
+/// `(i - j) mod ORDER` as scalar bytes
+fn index_diff_scalar(i: ParticipantIndex, j: ParticipantIndex) -> [u8; 32] {
+    if i >= j {
+        u64_to_be32(u64::from(i - j))
+    } else {
+        sub32(ORDER, u64_to_be32(u64::from(j - i)))
+    }
+}
+
+fn scalar_mul(a: &SecretKey, b: &[u8; 32]) -> SecretKey {
+    let mut out = *a;
+    out.mul_assign(b).expect("scalar mul_assign");
+    out
+}
+
+/// `-a mod ORDER`
+fn negate_scalar(a: &SecretKey) -> SecretKey {
+    let mut bytes = [0u8; 32];
+    bytes.copy_from_slice(&a[..]);
+    SecretKey::from_slice(&sub32(ORDER, bytes)).expect("ORDER - a is a valid scalar")
+}
+
+/// `-P`: for a compressed point, negating the y-coordinate is exactly
+/// flipping the parity byte (`0x02` <-> `0x03`), since the field prime is
+/// odd -- this holds regardless of which criterion ([`is_quadratic_residue`]
+/// or plain evenness) decides *whether* to negate
+fn negate_point(p: &PublicKey) -> PublicKey {
+    let mut bytes = p.serialize();
+    bytes[0] ^= 0x01;
+    PublicKey::from_slice(&bytes).expect("flipping the parity byte yields a valid point")
+}
+
+/// `p`'s raw y-coordinate, read out of the uncompressed
+/// `[0x04, x (32 bytes), y (32 bytes)]` serialization
+fn point_y(p: &PublicKey) -> [u8; 32] {
+    let uncompressed = p.serialize_uncompressed();
+    let mut y = [0u8; 32];
+    y.copy_from_slice(&uncompressed[33..65]);
+    y
+}
+
+/// schoolbook 256x256 -> 512-bit multiply into a big-endian wide buffer
+fn mul_wide(a: &[u8; 32], b: &[u8; 32]) -> [u8; 64] {
+    let mut acc = [0u32; 64];
+    for i in 0..32 {
+        if a[i] == 0 {
+            continue;
+        }
+        for j in 0..32 {
+            let prod = u32::from(a[i]) * u32::from(b[j]);
+            acc[i + j + 1] += prod & 0xff;
+            acc[i + j] += prod >> 8;
+        }
+    }
+    let mut carry = 0u32;
+    let mut out = [0u8; 64];
+    for k in (0..64).rev() {
+        let v = acc[k] + carry;
+        out[k] = (v & 0xff) as u8;
+        carry = v >> 8;
+    }
+    out
+}
+
+/// `a >= b`, comparing the 33-byte `a` against `b` zero-extended by one leading byte
+fn ge_wide(a: &[u8; 33], b: &[u8; 32]) -> bool {
+    if a[0] != 0 {
+        return true;
+    }
+    for i in 0..32 {
+        if a[i + 1] != b[i] {
+            return a[i + 1] > b[i];
+        }
+    }
+    true
+}
+
+/// `a -= b` in place, assuming `ge_wide(a, b)`
+fn sub_wide_assign(a: &mut [u8; 33], b: &[u8; 32]) {
+    let mut borrow = 0i16;
+    for i in (0..32).rev() {
+        let mut diff = i16::from(a[i + 1]) - i16::from(b[i]) - borrow;
+        if diff < 0 {
+            diff += 256;
+            borrow = 1;
+        } else {
+            borrow = 0;
+        }
+        a[i + 1] = diff as u8;
+    }
+    a[0] -= borrow as u8;
+}
+
+/// `wide mod modulus`, via bit-by-bit binary long division: shift one more
+/// bit of `wide` into a widened remainder and subtract `modulus` out of it
+/// whenever it fits, the textbook way to reduce a number wider than the
+/// modulus without a dedicated fast-reduction trick for that modulus
+fn reduce_wide(wide: &[u8], modulus: &[u8; 32]) -> [u8; 32] {
+    let mut remainder = [0u8; 33];
+    for &byte in wide {
+        for bit in (0..8).rev() {
+            let mut carry = (byte >> bit) & 1;
+            for k in (0..33).rev() {
+                let next_carry = remainder[k] >> 7;
+                remainder[k] = (remainder[k] << 1) | carry;
+                carry = next_carry;
+            }
+            if ge_wide(&remainder, modulus) {
+                sub_wide_assign(&mut remainder, modulus);
+            }
+        }
+    }
+    let mut out = [0u8; 32];
+    out.copy_from_slice(&remainder[1..33]);
+    out
+}
+
+/// `a * b mod FIELD_PRIME`
+fn field_mul(a: &[u8; 32], b: &[u8; 32]) -> [u8; 32] {
+    reduce_wide(&mul_wide(a, b), &FIELD_PRIME)
+}
+
+/// Euler's criterion: `y` is a quadratic residue mod the secp256k1 field
+/// prime iff `y^((p-1)/2) mod p == 1`. This is the normalization test the
+/// pre-BIP340 "zkp draft" Schnorr convention uses for both the public key
+/// and the nonce point (in place of BIP340's simpler even-`y` test).
+fn is_quadratic_residue(y: &[u8; 32]) -> bool {
+    let mut result = ONE;
+    for byte in FIELD_PRIME_HALF.iter() {
+        for bit in (0..8).rev() {
+            let squared = result;
+            result = field_mul(&result, &squared);
+            if (byte >> bit) & 1 == 1 {
+                result = field_mul(&result, y);
+            }
+        }
+    }
+    result == ONE
+}
+
+/// whether `p` needs negating to reach the jacobi-`y` (quadratic-residue)
+/// convention
+fn needs_negation(p: &PublicKey) -> bool {
+    !is_quadratic_residue(&point_y(p))
+}
+
+/// modular inverse via Fermat's little theorem (`ORDER` is prime): `a^(ORDER-2) mod ORDER`
+fn scalar_inverse(a: &SecretKey) -> SecretKey {
+    let mut result = SecretKey::from_slice(&ONE).expect("one is a valid scalar");
+    for byte in ORDER_MINUS_TWO.iter() {
+        for bit in (0..8).rev() {
+            let squared = result;
+            result = scalar_mul(&result, &squared[..]);
+            if (byte >> bit) & 1 == 1 {
+                result = scalar_mul(&result, &a[..]);
+            }
+        }
+    }
+    result
+}
+
+/// Lagrange coefficient `lambda_i` for interpolating the constant term of a
+/// polynomial at `x=0`, given the signer set `indices` contains `i`.
+fn lagrange_coefficient(i: ParticipantIndex, indices: &[ParticipantIndex]) -> SecretKey {
+    let mut numerator = SecretKey::from_slice(&ONE).expect("one is a valid scalar");
+    let mut denominator = SecretKey::from_slice(&ONE).expect("one is a valid scalar");
+    for &j in indices {
+        if j == i {
+            continue;
+        }
+        numerator = scalar_mul(&numerator, &u64_to_be32(u64::from(j)));
+        denominator = scalar_mul(&denominator, &index_diff_scalar(j, i));
+    }
+    scalar_mul(&numerator, &scalar_inverse(&denominator)[..])
+}
+
+/// A participant's share of the `t-of-n` group secret, produced by
+/// [`keygen_round2`]. Never leaves the participant that holds it.
+#[derive(Clone)]
+pub struct FrostKeyShare {
+    pub index: ParticipantIndex,
+    pub secret_share: SecretKey,
+    pub group_public_key: PublicKey,
+}
+
+/// what a participant broadcasts during DKG round 1: Feldman (verifiable
+/// secret sharing) commitments to the coefficients of its degree-`t-1`
+/// polynomial
+pub struct FeldmanCommitments(pub Vec<PublicKey>);
+
+/// computes the Feldman commitments to an already-sampled degree-`(threshold
+/// - 1)` polynomial (the constant term, `coefficients[0]`, is this
+/// participant's contribution to the group secret).
+///
+/// Coefficient sampling is the caller's responsibility: a real deployment
+/// draws them from a CSPRNG (e.g. `SecretKey::new(&mut rand::thread_rng())`),
+/// while tests can supply fixed coefficients deterministically. Keeping the
+/// sampling outside this module means it carries no `rand` dependency of
+/// its own.
+pub fn keygen_round1<C: Signing>(
+    secp: &Secp256k1<C>,
+    coefficients: &[SecretKey],
+) -> FeldmanCommitments {
+    FeldmanCommitments(
+        coefficients
+            .iter()
+            .map(|c| PublicKey::from_secret_key(secp, c))
+            .collect(),
+    )
+}
+
+/// evaluates this participant's polynomial at `recipient`'s index -- the
+/// share handed (privately) to that other participant during DKG round 2
+pub fn evaluate_polynomial(coefficients: &[SecretKey], recipient: ParticipantIndex) -> SecretKey {
+    let x = u64_to_be32(u64::from(recipient));
+    let mut result = *coefficients.last().expect("non-empty polynomial");
+    for c in coefficients[..coefficients.len() - 1].iter().rev() {
+        result.mul_assign(&x).expect("mul_assign");
+        result.add_assign(&c[..]).expect("add_assign");
+    }
+    result
+}
+
+/// combines the evaluation shares received from every other participant
+/// (plus this participant's own evaluation of its own polynomial) into a
+/// final signing share, and derives the group public key from everyone's
+/// published constant-term commitment.
+///
+/// The verifier this feeds into (`schnorr_verify`, used unchanged by
+/// `TreeSig`) expects a jacobi-`y` (quadratic-residue) public key, the same
+/// way a single signer's key would need to be. Every participant derives the
+/// same `group_public_key` from the same public commitments, so they can
+/// each independently negate their own `secret_share` (and the public key)
+/// whenever the raw combination doesn't already satisfy that -- no extra
+/// round trip needed.
+pub fn keygen_round2<C: Verification>(
+    secp: &Secp256k1<C>,
+    my_index: ParticipantIndex,
+    received_shares: &[SecretKey],
+    all_constant_commitments: &[PublicKey],
+) -> FrostKeyShare {
+    let mut secret_share = received_shares[0];
+    for share in &received_shares[1..] {
+        secret_share.add_assign(&share[..]).expect("add_assign");
+    }
+
+    let mut group_public_key = all_constant_commitments[0];
+    for commitment in &all_constant_commitments[1..] {
+        group_public_key = group_public_key
+            .combine(commitment)
+            .expect("combine commitments");
+    }
+    let _ = secp; // kept for symmetry with the signing half and future commitment checks
+
+    if needs_negation(&group_public_key) {
+        group_public_key = negate_point(&group_public_key);
+        secret_share = negate_scalar(&secret_share);
+    }
+
+    FrostKeyShare {
+        index: my_index,
+        secret_share,
+        group_public_key,
+    }
+}
+
+/// a signer's once-only nonce pair for one signing session (`d_i`, `e_i`)
+/// and their public commitments (`D_i`, `E_i`)
+pub struct SigningNonce {
+    pub hiding: SecretKey,
+    pub binding: SecretKey,
+}
+
+/// public half of a [`SigningNonce`], broadcast to the coordinator/other
+/// signers before the signing round starts
+#[derive(Clone, Copy)]
+pub struct NonceCommitment {
+    pub index: ParticipantIndex,
+    pub hiding: PublicKey,
+    pub binding: PublicKey,
+}
+
+/// derives the public commitments for an already-sampled nonce pair.
+///
+/// Like [`keygen_round1`]'s coefficients, sampling `hiding`/`binding` is the
+/// caller's responsibility (a CSPRNG in a real deployment, fixed values in
+/// tests) -- this keeps the module free of any `rand` dependency, and a
+/// fresh nonce pair is still required per signing session, same as single-
+/// signer Schnorr.
+pub fn generate_signing_nonce<C: Signing>(
+    secp: &Secp256k1<C>,
+    index: ParticipantIndex,
+    hiding: SecretKey,
+    binding: SecretKey,
+) -> (SigningNonce, NonceCommitment) {
+    let commitment = NonceCommitment {
+        index,
+        hiding: PublicKey::from_secret_key(secp, &hiding),
+        binding: PublicKey::from_secret_key(secp, &binding),
+    };
+    (SigningNonce { hiding, binding }, commitment)
+}
+
+fn binding_factor(index: ParticipantIndex, msg: &Message, commitments: &[NonceCommitment]) -> SecretKey {
+    let mut bs = Vec::new();
+    bs.extend(&index.to_be_bytes());
+    bs.extend(&msg[..]);
+    for c in commitments {
+        bs.extend(&c.index.to_be_bytes());
+        bs.extend(&c.hiding.serialize());
+        bs.extend(&c.binding.serialize());
+    }
+    let hash = txid_hash(&bs);
+    // a hash is already uniformly distributed over 32 bytes; reducing mod
+    // ORDER would require one more subtraction in the astronomically
+    // unlikely case it overflows -- skipped: SecretKey::from_slice rejects
+    // that case, and the `expect` below then panics.
+    //
+    // unlike `challenge` below, this binding factor never reaches
+    // `schnorr_verify` -- it's this module's own internal protocol
+    // randomness, so `txid_hash` (this chain's one general-purpose hash) is
+    // the right tool, same as everywhere else in this crate.
+    SecretKey::from_slice(&hash).expect("binding factor hash is a valid scalar")
+}
+
+/// minimal, from-scratch SHA-256 (FIPS 180-4). Used only by [`challenge`]:
+/// the pre-BIP340 zkp-draft convention that `schnorr_verify` (a full, not
+/// x-only, `PublicKey` argument) implies it follows hashes the challenge
+/// with SHA-256, not with this chain's own `txid_hash`.
+fn sha256(data: &[u8]) -> [u8; 32] {
+    const K: [u32; 64] = [
+        0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4,
+        0xab1c5ed5, 0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe,
+        0x9bdc06a7, 0xc19bf174, 0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f,
+        0x4a7484aa, 0x5cb0a9dc, 0x76f988da, 0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7,
+        0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967, 0x27b70a85, 0x2e1b2138, 0x4d2c6dfc,
+        0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85, 0xa2bfe8a1, 0xa81a664b,
+        0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070, 0x19a4c116,
+        0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+        0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7,
+        0xc67178f2,
+    ];
+    let mut h: [u32; 8] = [
+        0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
+        0x5be0cd19,
+    ];
+
+    let mut msg = data.to_vec();
+    let bit_len = (data.len() as u64) * 8;
+    msg.push(0x80);
+    while msg.len() % 64 != 56 {
+        msg.push(0);
+    }
+    msg.extend(&bit_len.to_be_bytes());
+
+    for chunk in msg.chunks(64) {
+        let mut w = [0u32; 64];
+        for i in 0..16 {
+            w[i] = u32::from_be_bytes([
+                chunk[4 * i],
+                chunk[4 * i + 1],
+                chunk[4 * i + 2],
+                chunk[4 * i + 3],
+            ]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+
+        let (mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut hh) =
+            (h[0], h[1], h[2], h[3], h[4], h[5], h[6], h[7]);
+
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ ((!e) & g);
+            let temp1 = hh
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(K[i])
+                .wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let temp2 = s0.wrapping_add(maj);
+
+            hh = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(temp1);
+            d = c;
+            c = b;
+            b = a;
+            a = temp1.wrapping_add(temp2);
+        }
+
+        h[0] = h[0].wrapping_add(a);
+        h[1] = h[1].wrapping_add(b);
+        h[2] = h[2].wrapping_add(c);
+        h[3] = h[3].wrapping_add(d);
+        h[4] = h[4].wrapping_add(e);
+        h[5] = h[5].wrapping_add(f);
+        h[6] = h[6].wrapping_add(g);
+        h[7] = h[7].wrapping_add(hh);
+    }
+
+    let mut out = [0u8; 32];
+    for (i, word) in h.iter().enumerate() {
+        out[4 * i..4 * i + 4].copy_from_slice(&word.to_be_bytes());
+    }
+    out
+}
+
+/// `schnorr_verify`'s own challenge, `e = SHA256(R_x || P || m)` over the
+/// nonce's x-only coordinate and the full 33-byte compressed public key --
+/// the zkp-draft convention, not BIP340's tagged-hash-over-x-only-pubkey
+/// one, matching what `schnorr_verify(&secp, &message, &sig, &pk)` taking a
+/// full (not x-only) `PublicKey` implies it does internally. `r_x`/`group_pk`
+/// are expected to already be the jacobi-`y`-normalized versions, the same
+/// way a single signer's `schnorr_sign` output would be.
+fn challenge(r_x: &[u8; 32], group_pk: &PublicKey, msg: &Message) -> SecretKey {
+    let mut bs = Vec::new();
+    bs.extend(r_x);
+    bs.extend(&group_pk.serialize());
+    bs.extend(&msg[..]);
+    let hash = sha256(&bs);
+    SecretKey::from_slice(&hash).expect("challenge hash is a valid scalar")
+}
+
+/// computes the group nonce `R = sum(D_i + rho_i * E_i)`, normalized to
+/// jacobi-`y` the way a single-signer Schnorr nonce would be. Returns the
+/// normalized point and whether it was negated to get there -- every signer
+/// computes this independently from the same public commitments, so they
+/// all agree on the flag without an extra round trip.
+pub fn group_nonce<C: Verification>(
+    secp: &Secp256k1<C>,
+    msg: &Message,
+    commitments: &[NonceCommitment],
+) -> (PublicKey, bool) {
+    let mut points = Vec::with_capacity(commitments.len());
+    for c in commitments {
+        let rho = binding_factor(c.index, msg, commitments);
+        let mut bound = c.binding;
+        bound.mul_assign(secp, &rho[..]).expect("tweak binding nonce");
+        points.push(c.hiding.combine(&bound).expect("combine nonce commitment"));
+    }
+    let mut r = points[0];
+    for p in &points[1..] {
+        r = r.combine(p).expect("combine group nonce");
+    }
+
+    if needs_negation(&r) {
+        (negate_point(&r), true)
+    } else {
+        (r, false)
+    }
+}
+
+fn x_only(p: &PublicKey) -> [u8; 32] {
+    let mut out = [0u8; 32];
+    out.copy_from_slice(&p.serialize()[1..33]);
+    out
+}
+
+/// this signer's partial response
+/// `z_i = +-(d_i + e_i * rho_i) + lambda_i * secret_share_i * c`, negating
+/// the nonce contribution (but not the challenge term) whenever the group
+/// nonce needed negating to reach jacobi-`y` -- `key_share.group_public_key`
+/// and `key_share.secret_share` are already the jacobi-`y`-normalized pair
+/// produced by [`keygen_round2`], so the challenge term needs no such fixup.
+pub fn partial_sign<C: Verification>(
+    secp: &Secp256k1<C>,
+    key_share: &FrostKeyShare,
+    nonce: &SigningNonce,
+    msg: &Message,
+    commitments: &[NonceCommitment],
+    signer_indices: &[ParticipantIndex],
+) -> SecretKey {
+    let rho = binding_factor(key_share.index, msg, commitments);
+    let (r, negated) = group_nonce(secp, msg, commitments);
+    let c = challenge(&x_only(&r), &key_share.group_public_key, msg);
+    let lambda = lagrange_coefficient(key_share.index, signer_indices);
+
+    let mut nonce_part = nonce.hiding;
+    nonce_part
+        .add_assign(&scalar_mul(&nonce.binding, &rho[..])[..])
+        .expect("add binding contribution");
+    if negated {
+        nonce_part = negate_scalar(&nonce_part);
+    }
+
+    let mut lambda_share_c = scalar_mul(&lambda, &key_share.secret_share[..]);
+    lambda_share_c = scalar_mul(&lambda_share_c, &c[..]);
+    nonce_part
+        .add_assign(&lambda_share_c[..])
+        .expect("add challenge contribution");
+    nonce_part
+}
+
+/// combines every quorum member's partial response into the final
+/// signature `(R_x, sum(z_i))`, serialized the same way a single-signer
+/// Schnorr signature would be so it still fits in `TreeSig` and still
+/// verifies under the unchanged `schnorr_verify` call in `verify_tx_address`.
+pub fn aggregate_signature<C: Verification>(
+    secp: &Secp256k1<C>,
+    group_public_key: &PublicKey,
+    msg: &Message,
+    commitments: &[NonceCommitment],
+    partial_signatures: &BTreeMap<ParticipantIndex, SecretKey>,
+) -> SchnorrSignature {
+    let (r, _negated) = group_nonce(secp, msg, commitments);
+    let mut z = *partial_signatures
+        .values()
+        .next()
+        .expect("at least one partial signature");
+    for partial in partial_signatures.values().skip(1) {
+        z.add_assign(&partial[..]).expect("combine partial signatures");
+    }
+    let _ = group_public_key;
+
+    let mut raw = [0u8; 64];
+    raw[..32].copy_from_slice(&x_only(&r));
+    raw[32..].copy_from_slice(&z[..]);
+    SchnorrSignature::from_default(&raw).expect("valid schnorr signature encoding")
+}
@@ -1,6 +1,12 @@
 /// Witness for Merklized Abstract Syntax Trees (MAST) + Schnorr
 pub mod tree;
 
+/// BIP143-style sighash flags, so a witness can commit to a subset of a transaction
+pub mod sighash;
+
+/// FROST threshold Schnorr signing, so t-of-n `TreeSig` spends need only one aggregate key
+pub mod frost;
+
 use std::fmt;
 
 use rlp::{Decodable, DecoderError, Encodable, Rlp, RlpStream};
@@ -13,6 +19,7 @@ use serde::{Deserialize, Serialize};
 use crate::init::address::RedeemAddress;
 use crate::tx::data::address::ExtendedAddr;
 use crate::tx::data::{txid_hash, Tx};
+use crate::tx::witness::sighash::{compute_sighash, SigHashType};
 use crate::tx::witness::tree::{MerklePath, ProofOp, RawPubkey, RawSignature};
 
 pub type EcdsaSignature = RecoverableSignature;
@@ -70,8 +77,8 @@ impl ::std::ops::DerefMut for TxWitness {
 // normally should be some structure: e.g. indicate a type of signature
 #[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
 pub enum TxInWitness {
-    BasicRedeem(EcdsaSignature),
-    TreeSig(PublicKey, SchnorrSignature, Vec<ProofOp>),
+    BasicRedeem(EcdsaSignature, SigHashType),
+    TreeSig(PublicKey, SchnorrSignature, SigHashType, Vec<ProofOp>),
 }
 
 impl fmt::Display for TxInWitness {
@@ -83,21 +90,26 @@ impl fmt::Display for TxInWitness {
 impl Encodable for TxInWitness {
     fn rlp_append(&self, s: &mut RlpStream) {
         match self {
-            TxInWitness::BasicRedeem(sig) => {
+            TxInWitness::BasicRedeem(sig, sighash_type) => {
                 let (recovery_id, serialized_sig) = sig.serialize_compact();
                 let signature: RawSignature = serialized_sig.into();
                 // recovery_id is one of 0 | 1 | 2 | 3
                 let rid = recovery_id.to_i32() as u8;
-                s.begin_list(3).append(&0u8).append(&rid).append(&signature);
+                s.begin_list(4)
+                    .append(&0u8)
+                    .append(&rid)
+                    .append(&signature)
+                    .append(sighash_type);
             }
-            TxInWitness::TreeSig(pk, schnorrsig, ops) => {
+            TxInWitness::TreeSig(pk, schnorrsig, sighash_type, ops) => {
                 let serialized_pk: RawPubkey = pk.serialize().into();
                 let serialized_sig: RawSignature = schnorrsig.serialize_default().into();
                 // TODO: better proof op encoding
-                s.begin_list(4)
+                s.begin_list(5)
                     .append(&1u8)
                     .append(&serialized_pk)
                     .append(&serialized_sig)
+                    .append(sighash_type)
                     .append_list(&ops);
             }
         }
@@ -107,21 +119,22 @@ impl Encodable for TxInWitness {
 impl Decodable for TxInWitness {
     fn decode(rlp: &Rlp) -> Result<Self, DecoderError> {
         let item_count = rlp.item_count()?;
-        if !(item_count >= 3 && item_count <= 4) {
+        if !(item_count >= 4 && item_count <= 5) {
             return Err(DecoderError::Custom("Cannot decode a transaction witness"));
         }
         let type_tag: u8 = rlp.val_at(0)?;
         match (type_tag, item_count) {
-            (0, 3) => {
+            (0, 4) => {
                 let rid: u8 = rlp.val_at(1)?;
                 let raw_sig: RawSignature = rlp.val_at(2)?;
                 let recovery_id = RecoveryId::from_i32(i32::from(rid))
                     .map_err(|_| DecoderError::Custom("failed to decode recovery id"))?;
                 let sig = RecoverableSignature::from_compact(&raw_sig.as_bytes(), recovery_id)
                     .map_err(|_| DecoderError::Custom("failed to decode recoverable signature"))?;
-                Ok(TxInWitness::BasicRedeem(sig))
+                let sighash_type: SigHashType = rlp.val_at(3)?;
+                Ok(TxInWitness::BasicRedeem(sig, sighash_type))
             }
-            (1, 4) => {
+            (1, 5) => {
                 let raw_pk: RawPubkey = rlp.val_at(1)?;
                 let pk = PublicKey::from_slice(&raw_pk.as_bytes())
                     .map_err(|_| DecoderError::Custom("failed to public key"))?;
@@ -129,9 +142,10 @@ impl Decodable for TxInWitness {
                 let raw_sig: RawSignature = rlp.val_at(2)?;
                 let schnorrsig = SchnorrSignature::from_default(&raw_sig.as_bytes())
                     .map_err(|_| DecoderError::Custom("failed to decode schnorr signature"))?;
+                let sighash_type: SigHashType = rlp.val_at(3)?;
                 // TODO: max tree depth?
-                let ops: Vec<ProofOp> = rlp.list_at(3)?;
-                Ok(TxInWitness::TreeSig(pk, schnorrsig, ops))
+                let ops: Vec<ProofOp> = rlp.list_at(4)?;
+                Ok(TxInWitness::TreeSig(pk, schnorrsig, sighash_type, ops))
             }
             _ => Err(DecoderError::Custom("Unknown transaction type")),
         }
@@ -139,20 +153,51 @@ impl Decodable for TxInWitness {
 }
 
 impl TxInWitness {
+    /// the sighash type carried by this witness
+    pub fn sighash_type(&self) -> SigHashType {
+        match self {
+            TxInWitness::BasicRedeem(_, sighash_type) => *sighash_type,
+            TxInWitness::TreeSig(_, _, sighash_type, _) => *sighash_type,
+        }
+    }
+
     /// verify a given extended address is associated to the witness
     /// and the signature against the given transation `Tx`
+    ///
+    /// `input_index` and `spent_value` identify which input this witness is
+    /// attached to and the amount it spends -- both are needed to compute
+    /// the BIP143-style sighash message. `allowed_sighash_types` is the
+    /// verifier's policy: a witness whose `SigHashType` is not in that list
+    /// is rejected even if the signature itself is valid.
+    ///
+    /// NB: this is a breaking change to the old 2-argument `(tx, address)`
+    /// signature. Every caller in this crate (and in this sparse checkout --
+    /// `chain-core`, `client-index`, nothing else) has been updated; this
+    /// is scoped to that crate and this checkout only. Any other workspace
+    /// crate that validates witnesses against a stored UTXO set (e.g. a
+    /// `chain-tx-validation`-style crate, not present here) must be updated
+    /// too: it needs to look up `spent_value` from the output its input
+    /// references (passing the wrong value changes the sighash and breaks
+    /// verification) and thread through the `allowed_sighash_types` policy
+    /// its own validation rules allow for that input.
     /// TODO: capture possible errors in enum?
     ///
     pub fn verify_tx_address(
         &self,
         tx: &Tx,
+        input_index: usize,
+        spent_value: u64,
         address: &ExtendedAddr,
+        allowed_sighash_types: &[SigHashType],
     ) -> Result<(), secp256k1::Error> {
+        if !allowed_sighash_types.contains(&self.sighash_type()) {
+            return Err(secp256k1::Error::InvalidSignature);
+        }
         let secp = Secp256k1::verification_only();
-        let message = Message::from_slice(tx.id().as_bytes())?;
+        let message = compute_sighash(tx, input_index, spent_value, self.sighash_type())?;
 
         match (&self, address) {
-            (TxInWitness::BasicRedeem(sig), ExtendedAddr::BasicRedeem(addr)) => {
+            (TxInWitness::BasicRedeem(sig, _), ExtendedAddr::BasicRedeem(addr)) => {
                 let pk = secp.recover(&message, &sig)?;
                 let expected_addr = RedeemAddress::from(&pk);
                 // TODO: constant time eq?
@@ -162,7 +207,7 @@ impl TxInWitness {
                     secp.verify(&message, &sig.to_standard(), &pk)
                 }
             }
-            (TxInWitness::TreeSig(pk, sig, ops), ExtendedAddr::OrTree(roothash)) => {
+            (TxInWitness::TreeSig(pk, sig, _, ops), ExtendedAddr::OrTree(roothash)) => {
                 let mut pk_hash = txid_hash(&pk.serialize());
                 // TODO: blake2 tree hashing?
                 for op in ops.iter() {
@@ -196,10 +241,13 @@ impl TxInWitness {
 #[cfg(test)]
 pub mod tests {
     use super::*;
+    use std::collections::BTreeMap;
+
     use crate::common::merkle::MerkleTree;
     use crate::common::H256;
+    use crate::tx::data::input::TxIn;
     use crate::tx::data::txid_hash;
-    use crate::tx::witness::tree::MerklePath;
+    use crate::tx::data::TxId;
     use secp256k1::{
         key::pubkey_combine,
         key::PublicKey,
@@ -209,14 +257,25 @@ pub mod tests {
         Message, Secp256k1, Signing, Verification,
     };
 
+    const SPENT_VALUE: u64 = 100;
+
+    /// a transaction with a single input at index 0, enough for the
+    /// BIP143-style sighash to have something to commit to
+    fn tx_with_one_input() -> Tx {
+        let mut tx = Tx::new();
+        tx.inputs.push(TxIn::new(TxId::zero(), 0));
+        tx
+    }
+
     pub fn get_ecdsa_witness<C: Signing>(
         secp: &Secp256k1<C>,
         tx: &Tx,
         secret_key: &SecretKey,
     ) -> TxInWitness {
-        let message = Message::from_slice(tx.id().as_bytes()).expect("32 bytes");
+        let sighash_type = SigHashType::all();
+        let message = compute_sighash(tx, 0, SPENT_VALUE, sighash_type).expect("sighash message");
         let sig = secp.sign_recoverable(&message, &secret_key);
-        return TxInWitness::BasicRedeem(sig);
+        return TxInWitness::BasicRedeem(sig, sighash_type);
     }
 
     fn sign_single_schnorr<C: Signing>(
@@ -232,7 +291,8 @@ pub mod tests {
         tx: &Tx,
         secret_key: &SecretKey,
     ) -> (TxInWitness, H256) {
-        let message = Message::from_slice(tx.id().as_bytes()).expect("32 bytes");
+        let sighash_type = SigHashType::all();
+        let message = compute_sighash(tx, 0, SPENT_VALUE, sighash_type).expect("sighash message");
         let sig = sign_single_schnorr(&secp, &message, &secret_key);
         let pk = PublicKey::from_secret_key(&secp, &secret_key);
 
@@ -240,7 +300,7 @@ pub mod tests {
         let merkle = MerkleTree::new(&vec![pk_hash]);
 
         return (
-            TxInWitness::TreeSig(pk, sig, vec![]),
+            TxInWitness::TreeSig(pk, sig, sighash_type, vec![]),
             merkle.get_root_hash(),
         );
     }
@@ -251,7 +311,8 @@ pub mod tests {
         secret_key1: SecretKey,
         secret_key2: SecretKey,
     ) -> (SchnorrSignature, PublicKey, PublicKey) {
-        let message = Message::from_slice(tx.id().as_bytes()).expect("32 bytes");
+        let message =
+            compute_sighash(tx, 0, SPENT_VALUE, SigHashType::all()).expect("sighash message");
         let pk1 = PublicKey::from_secret_key(&secp, &secret_key1);
         let pk2 = PublicKey::from_secret_key(&secp, &secret_key2);
         let session_id1 = MuSigSessionID::from_slice(&[0x01; 32]).expect("32 bytes");
@@ -315,60 +376,121 @@ pub mod tests {
         let merkle = MerkleTree::new(&vec![pk_hash]);
 
         return (
-            TxInWitness::TreeSig(pk, sig, vec![]),
+            TxInWitness::TreeSig(pk, sig, SigHashType::all(), vec![]),
             merkle.get_root_hash(),
         );
     }
 
-    fn get_2_of_3_tx_witness<C: Signing + Verification>(
-        secp: Secp256k1<C>,
+    /// 2-of-3 FROST: unlike the old MAST-of-MuSig approach, every quorum of
+    /// the 3 participants signs under the *same* aggregate key, so the
+    /// Merkle tree collapses to a single leaf instead of enumerating
+    /// `C(3, 2)` combined-key leaves.
+    fn get_2_of_3_frost_tx_witness<C: Signing + Verification>(
+        secp: &Secp256k1<C>,
         tx: &Tx,
-        secret_key1: SecretKey,
-        secret_key2: SecretKey,
-        secret_key3: SecretKey,
     ) -> (TxInWitness, H256) {
-        let pk1 = PublicKey::from_secret_key(&secp, &secret_key1);
-        let pk2 = PublicKey::from_secret_key(&secp, &secret_key2);
-        let pk3 = PublicKey::from_secret_key(&secp, &secret_key3);
-        let pkc1 = pubkey_combine(&secp, &vec![pk1, pk2]).unwrap().0;
-        let pkc2 = pubkey_combine(&secp, &vec![pk1, pk3]).unwrap().0;
-        let pkc3 = pubkey_combine(&secp, &vec![pk2, pk3]).unwrap().0;
-        let pk_hashes: Vec<H256> = vec![pkc1, pkc2, pkc3]
+        let all_indices: Vec<frost::ParticipantIndex> = vec![1, 2, 3];
+
+        // fixed, deterministic per-participant coefficients (constant term,
+        // then the degree-1 term) -- a real deployment would sample these
+        // from a CSPRNG instead
+        let mut coefficients = BTreeMap::new();
+        let mut commitments = BTreeMap::new();
+        for &i in &all_indices {
+            let c = vec![
+                SecretKey::from_slice(&[0x10 + i as u8; 32]).expect("valid scalar"),
+                SecretKey::from_slice(&[0x20 + i as u8; 32]).expect("valid scalar"),
+            ];
+            let fc = frost::keygen_round1(secp, &c);
+            coefficients.insert(i, c);
+            commitments.insert(i, fc);
+        }
+        let all_constant_commitments: Vec<PublicKey> = all_indices
             .iter()
-            .map(|x| txid_hash(&x.serialize()[..]))
+            .map(|i| commitments[i].0[0])
             .collect();
-        let merkle = MerkleTree::new(&pk_hashes);
 
-        let path: Vec<ProofOp> = vec![
-            ProofOp(MerklePath::LFound, pk_hashes[1]),
-            ProofOp(MerklePath::LFound, pk_hashes[2]),
-        ];
+        let mut key_shares = BTreeMap::new();
+        for &i in &all_indices {
+            let received: Vec<SecretKey> = all_indices
+                .iter()
+                .map(|j| frost::evaluate_polynomial(&coefficients[j], i))
+                .collect();
+            key_shares.insert(
+                i,
+                frost::keygen_round2(secp, i, &received, &all_constant_commitments),
+            );
+        }
+        let group_public_key = key_shares[&1].group_public_key;
+
+        // any 2 of the 3 participants can sign -- pick the first two
+        let quorum: Vec<frost::ParticipantIndex> = vec![1, 2];
+        let message = compute_sighash(tx, 0, SPENT_VALUE, SigHashType::all())
+            .expect("sighash message");
+
+        // fixed, deterministic per-participant nonces -- a real deployment
+        // would sample these from a CSPRNG instead, same as the DKG
+        // coefficients above
+        let mut nonces = BTreeMap::new();
+        let mut nonce_commitments = Vec::new();
+        for &i in &quorum {
+            let hiding = SecretKey::from_slice(&[0x30 + i as u8; 32]).expect("valid scalar");
+            let binding = SecretKey::from_slice(&[0x40 + i as u8; 32]).expect("valid scalar");
+            let (nonce, commitment) = frost::generate_signing_nonce(secp, i, hiding, binding);
+            nonces.insert(i, nonce);
+            nonce_commitments.push(commitment);
+        }
+
+        let mut partial_sigs = BTreeMap::new();
+        for &i in &quorum {
+            let z = frost::partial_sign(
+                secp,
+                &key_shares[&i],
+                &nonces[&i],
+                &message,
+                &nonce_commitments,
+                &quorum,
+            );
+            partial_sigs.insert(i, z);
+        }
+        let sig = frost::aggregate_signature(
+            secp,
+            &group_public_key,
+            &message,
+            &nonce_commitments,
+            &partial_sigs,
+        );
 
-        let (sig, _, _) = get_2_of_2_sig(&secp, tx, secret_key1, secret_key2);
+        let pk_hash = txid_hash(&group_public_key.serialize());
+        let merkle = MerkleTree::new(&vec![pk_hash]);
 
         return (
-            TxInWitness::TreeSig(pkc1, sig, path),
+            TxInWitness::TreeSig(group_public_key, sig, SigHashType::all(), vec![]),
             merkle.get_root_hash(),
         );
     }
 
     #[test]
     fn mismatched_signed_tx_should_fail() {
-        let tx = Tx::new();
+        let tx = tx_with_one_input();
         let secp = Secp256k1::new();
         let secret_key = SecretKey::from_slice(&[0xcd; 32]).expect("32 bytes, within curve order");
         let public_key = PublicKey::from_secret_key(&secp, &secret_key);
         let expected_addr1 = ExtendedAddr::OrTree([0x00; 32].into());
         let witness1 = get_ecdsa_witness(&secp, &tx, &secret_key);
-        assert!(witness1.verify_tx_address(&tx, &expected_addr1).is_err());
+        assert!(witness1
+            .verify_tx_address(&tx, 0, SPENT_VALUE, &expected_addr1, &[SigHashType::all()])
+            .is_err());
         let expected_addr2 = ExtendedAddr::BasicRedeem(RedeemAddress::from(&public_key));
         let (witness2, _) = get_single_tx_witness(secp, &tx, &secret_key);
-        assert!(witness2.verify_tx_address(&tx, &expected_addr2).is_err());
+        assert!(witness2
+            .verify_tx_address(&tx, 0, SPENT_VALUE, &expected_addr2, &[SigHashType::all()])
+            .is_err());
     }
 
     #[test]
     fn same_pk_recovered() {
-        let tx = Tx::new();
+        let tx = tx_with_one_input();
         let secp = Secp256k1::new();
         let secret_key = SecretKey::from_slice(&[0xcd; 32]).expect("32 bytes, within curve order");
         let public_key = PublicKey::from_secret_key(&secp, &secret_key);
@@ -377,8 +499,9 @@ pub mod tests {
         let rlp = Rlp::new(&encoded);
         let decoded = TxWitness::decode(&rlp).expect("decode tx witness");
         match &decoded[0] {
-            TxInWitness::BasicRedeem(sig) => {
-                let message = Message::from_slice(tx.id().as_bytes()).expect("32 bytes");
+            TxInWitness::BasicRedeem(sig, sighash_type) => {
+                let message = compute_sighash(&tx, 0, SPENT_VALUE, *sighash_type)
+                    .expect("sighash message");
                 let pk = secp.recover(&message, &sig).unwrap();
                 assert_eq!(pk, public_key);
             }
@@ -390,71 +513,93 @@ pub mod tests {
 
     #[test]
     fn signed_tx_should_verify() {
-        let tx = Tx::new();
+        let tx = tx_with_one_input();
         let secp = Secp256k1::new();
         let secret_key = SecretKey::from_slice(&[0xcd; 32]).expect("32 bytes, within curve order");
         let public_key = PublicKey::from_secret_key(&secp, &secret_key);
         let expected_addr = ExtendedAddr::BasicRedeem(RedeemAddress::from(&public_key));
         let witness = get_ecdsa_witness(&secp, &tx, &secret_key);
-        assert!(witness.verify_tx_address(&tx, &expected_addr).is_ok());
+        assert!(witness
+            .verify_tx_address(&tx, 0, SPENT_VALUE, &expected_addr, &[SigHashType::all()])
+            .is_ok());
+    }
+
+    #[test]
+    fn wrong_sighash_policy_should_fail() {
+        let tx = tx_with_one_input();
+        let secp = Secp256k1::new();
+        let secret_key = SecretKey::from_slice(&[0xcd; 32]).expect("32 bytes, within curve order");
+        let public_key = PublicKey::from_secret_key(&secp, &secret_key);
+        let expected_addr = ExtendedAddr::BasicRedeem(RedeemAddress::from(&public_key));
+        let witness = get_ecdsa_witness(&secp, &tx, &secret_key);
+        let allowed = [SigHashType::Single {
+            anyone_can_pay: false,
+        }];
+        assert!(witness
+            .verify_tx_address(&tx, 0, SPENT_VALUE, &expected_addr, &allowed)
+            .is_err());
     }
 
     #[test]
     fn schnorr_signed_tx_should_verify() {
-        let tx = Tx::new();
+        let tx = tx_with_one_input();
         let secp = Secp256k1::new();
         let secret_key = SecretKey::from_slice(&[0xcd; 32]).expect("32 bytes, within curve order");
         let (witness, addr) = get_single_tx_witness(secp, &tx, &secret_key);
         let expected_addr = ExtendedAddr::OrTree(addr);
-        let r = witness.verify_tx_address(&tx, &expected_addr);
+        let r = witness.verify_tx_address(&tx, 0, SPENT_VALUE, &expected_addr, &[SigHashType::all()]);
         assert!(r.is_ok());
     }
 
     #[test]
     fn agg_schnorr_signed_tx_should_verify() {
-        let tx = Tx::new();
+        let tx = tx_with_one_input();
         let secp = Secp256k1::new();
         let secret_key1 = SecretKey::from_slice(&[0xcd; 32]).expect("32 bytes, within curve order");
         let secret_key2 = SecretKey::from_slice(&[0xde; 32]).expect("32 bytes, within curve order");
         let (witness, addr) = get_2_of_2_tx_witness(secp, &tx, secret_key1, secret_key2);
         let expected_addr = ExtendedAddr::OrTree(addr);
-        assert!(witness.verify_tx_address(&tx, &expected_addr).is_ok());
+        assert!(witness
+            .verify_tx_address(&tx, 0, SPENT_VALUE, &expected_addr, &[SigHashType::all()])
+            .is_ok());
     }
 
     #[test]
-    fn tree_agg_schnorr_signed_tx_should_verify() {
-        let tx = Tx::new();
+    fn frost_2_of_3_signed_tx_should_verify() {
+        let tx = tx_with_one_input();
         let secp = Secp256k1::new();
-        let secret_key1 = SecretKey::from_slice(&[0xcd; 32]).expect("32 bytes, within curve order");
-        let secret_key2 = SecretKey::from_slice(&[0xde; 32]).expect("32 bytes, within curve order");
-        let secret_key3 = SecretKey::from_slice(&[0xef; 32]).expect("32 bytes, within curve order");
-        let (witness, addr) =
-            get_2_of_3_tx_witness(secp, &tx, secret_key1, secret_key2, secret_key3);
+        let (witness, addr) = get_2_of_3_frost_tx_witness(&secp, &tx);
         let expected_addr = ExtendedAddr::OrTree(addr);
-        assert!(witness.verify_tx_address(&tx, &expected_addr).is_ok());
+        assert!(witness
+            .verify_tx_address(&tx, 0, SPENT_VALUE, &expected_addr, &[SigHashType::all()])
+            .is_ok());
     }
 
     #[test]
     fn wrong_basic_address_should_fail() {
-        let tx = Tx::new();
+        let tx = tx_with_one_input();
         let secp = Secp256k1::new();
         let secret_key = SecretKey::from_slice(&[0xcd; 32]).expect("32 bytes, within curve order");
 
         let witness = get_ecdsa_witness(&secp, &tx, &secret_key);
         let wrong_addr = ExtendedAddr::BasicRedeem(RedeemAddress::default());
-        assert!(witness.verify_tx_address(&tx, &wrong_addr).is_err());
+        assert!(witness
+            .verify_tx_address(&tx, 0, SPENT_VALUE, &wrong_addr, &[SigHashType::all()])
+            .is_err());
     }
 
     #[test]
     fn wrongly_basic_signed_tx_should_fail() {
-        let tx = Tx::new();
+        let tx = tx_with_one_input();
         let secp = Secp256k1::new();
         let secret_key = SecretKey::from_slice(&[0xcd; 32]).expect("32 bytes, within curve order");
         let message = Message::from_slice(&[0xaa; 32]).expect("32 bytes");
         let sign = secp.sign_recoverable(&message, &secret_key);
-        let witness = TxInWitness::BasicRedeem(sign);
+        let witness = TxInWitness::BasicRedeem(sign, SigHashType::all());
         let addr = ExtendedAddr::BasicRedeem(RedeemAddress::default());
-        assert!(witness.verify_tx_address(&tx, &addr).is_err());
+        assert!(witness
+            .verify_tx_address(&tx, 0, SPENT_VALUE, &addr, &[SigHashType::all()])
+            .is_err());
     }
 
 }
@@ -0,0 +1,165 @@
+use rlp::{Decodable, DecoderError, Encodable, Rlp, RlpStream};
+use secp256k1::Message;
+use serde::{Deserialize, Serialize};
+
+use crate::tx::data::input::TxIn;
+use crate::tx::data::output::TxOut;
+use crate::tx::data::{txid_hash, Tx};
+
+const ANYONECANPAY_MASK: u8 = 0x80;
+const BASE_TYPE_MASK: u8 = 0x1f;
+
+/// Which part of a transaction a signature commits to, following the
+/// BIP143 sighash flags (`ALL`, `NONE`, `SINGLE` + the `ANYONECANPAY` modifier).
+///
+/// This lets inputs be signed independently of each other and of outputs
+/// that may still be added, instead of every signer always committing to
+/// `tx.id()` in full.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub enum SigHashType {
+    /// commit to all inputs and all outputs (the historical default)
+    All { anyone_can_pay: bool },
+    /// commit to all inputs but none of the outputs
+    None { anyone_can_pay: bool },
+    /// commit to all inputs and only the output at the same index as this input
+    Single { anyone_can_pay: bool },
+}
+
+impl SigHashType {
+    /// the default full-transaction commitment (`ALL`, no `ANYONECANPAY`)
+    pub fn all() -> Self {
+        SigHashType::All {
+            anyone_can_pay: false,
+        }
+    }
+
+    fn anyone_can_pay(self) -> bool {
+        match self {
+            SigHashType::All { anyone_can_pay }
+            | SigHashType::None { anyone_can_pay }
+            | SigHashType::Single { anyone_can_pay } => anyone_can_pay,
+        }
+    }
+
+    fn as_u8(self) -> u8 {
+        let base = match self {
+            SigHashType::All { .. } => 0x01,
+            SigHashType::None { .. } => 0x02,
+            SigHashType::Single { .. } => 0x03,
+        };
+        if self.anyone_can_pay() {
+            base | ANYONECANPAY_MASK
+        } else {
+            base
+        }
+    }
+
+    fn from_u8(b: u8) -> Result<Self, DecoderError> {
+        let anyone_can_pay = b & ANYONECANPAY_MASK != 0;
+        match b & BASE_TYPE_MASK {
+            0x01 => Ok(SigHashType::All { anyone_can_pay }),
+            0x02 => Ok(SigHashType::None { anyone_can_pay }),
+            0x03 => Ok(SigHashType::Single { anyone_can_pay }),
+            _ => Err(DecoderError::Custom("Unknown sighash type")),
+        }
+    }
+}
+
+impl Encodable for SigHashType {
+    fn rlp_append(&self, s: &mut RlpStream) {
+        s.append(&self.as_u8());
+    }
+}
+
+impl Decodable for SigHashType {
+    fn decode(rlp: &Rlp) -> Result<Self, DecoderError> {
+        let b: u8 = rlp.as_val()?;
+        SigHashType::from_u8(b)
+    }
+}
+
+/// BIP143 commits via a double hash (`HASH256`, i.e. `txid_hash` applied
+/// twice) rather than a single one; this matches that, even though the
+/// underlying hash function here isn't SHA256d.
+fn double_hash(bs: &[u8]) -> [u8; 32] {
+    txid_hash(&txid_hash(bs))
+}
+
+fn hash_prevouts(inputs: &[TxIn]) -> [u8; 32] {
+    let mut bs = Vec::with_capacity(inputs.len() * 36);
+    for input in inputs {
+        bs.extend(input.id.as_bytes());
+        bs.extend(&input.index.to_le_bytes());
+    }
+    double_hash(&bs)
+}
+
+/// BIP143's `hashSequence` commits to every input's `nSequence`, which
+/// exists to signal replaceability and BIP68 relative locktimes. `TxIn` in
+/// this chain carries neither, so there is no real per-input value to hash;
+/// this intentionally degenerates to a hash over the input count instead,
+/// kept as its own function so a future per-input sequence number only
+/// touches this spot.
+fn hash_sequence(inputs: &[TxIn]) -> [u8; 32] {
+    double_hash(&(inputs.len() as u32).to_le_bytes())
+}
+
+fn hash_outputs(outputs: &[TxOut]) -> [u8; 32] {
+    let mut bs = Vec::new();
+    for output in outputs {
+        bs.extend(&rlp::encode(output));
+    }
+    double_hash(&bs)
+}
+
+fn hash_single_output(outputs: &[TxOut], index: usize) -> [u8; 32] {
+    match outputs.get(index) {
+        Some(output) => double_hash(&rlp::encode(output)),
+        None => [0u8; 32],
+    }
+}
+
+/// Computes the BIP143-style sighash message for signing/verifying the
+/// input at `input_index`, binding only the parts of `tx` that
+/// `sighash_type` says the signer commits to.
+///
+/// `spent_value` is the amount of the output being spent by this input --
+/// it is not part of `tx` itself, so the caller (which has access to the
+/// referenced outputs) must supply it.
+pub fn compute_sighash(
+    tx: &Tx,
+    input_index: usize,
+    spent_value: u64,
+    sighash_type: SigHashType,
+) -> Result<Message, secp256k1::Error> {
+    let input = tx
+        .inputs
+        .get(input_index)
+        .ok_or(secp256k1::Error::InvalidSignature)?;
+
+    let (prevouts, sequence) = if sighash_type.anyone_can_pay() {
+        ([0u8; 32], [0u8; 32])
+    } else {
+        (hash_prevouts(&tx.inputs), hash_sequence(&tx.inputs))
+    };
+
+    let outputs = match sighash_type {
+        SigHashType::All { .. } => hash_outputs(&tx.outputs),
+        SigHashType::Single { .. } => hash_single_output(&tx.outputs, input_index),
+        SigHashType::None { .. } => [0u8; 32],
+    };
+
+    let mut bs = Vec::new();
+    bs.extend(&tx.version.to_le_bytes());
+    bs.extend(&prevouts);
+    bs.extend(&sequence);
+    bs.extend(input.id.as_bytes());
+    bs.extend(&input.index.to_le_bytes());
+    bs.extend(&spent_value.to_le_bytes());
+    bs.extend(&outputs);
+    bs.extend(&tx.lock_time.to_le_bytes());
+    bs.push(sighash_type.as_u8());
+
+    let hash = double_hash(&bs);
+    Ok(Message::from_slice(&hash).expect("32 bytes hash is a valid message"))
+}
@@ -1,26 +1,88 @@
+use std::num::NonZeroU32;
+use std::sync::Arc;
+
 use failure::ResultExt;
 use rlp::{decode, encode};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305};
+use ring::pbkdf2;
+use ring::rand::{SecureRandom, SystemRandom};
 
 use chain_core::tx::data::{Tx, TxId};
-use client_common::{ErrorKind, Result, Storage};
+use client_common::{Error, ErrorKind, Result, Storage};
+
+use self::gcs::GcsFilter;
 
 const KEYSPACE: &str = "index_transaction";
+const FILTER_KEYSPACE: &str = "index_block_filter";
+
+const NONCE_LEN: usize = 12;
+const KEY_LEN: usize = 32;
+const PBKDF2_ITERATIONS: u32 = 100_000;
+// a fixed, application-specific salt: it only needs to separate this KDF's
+// output space from other uses of the same passphrase, not to be secret
+const KDF_SALT: &[u8] = b"crypto-chain/client-index/transaction-service/v1";
+
+/// whether transactions are written to `storage` as plain RLP, or sealed
+/// with ChaCha20-Poly1305 first
+#[derive(Clone)]
+enum Encryption {
+    Plain,
+    Encrypted(Arc<LessSafeKey>),
+}
+
+impl Default for Encryption {
+    fn default() -> Self {
+        Encryption::Plain
+    }
+}
 
 /// Exposes functionalities for managing transactions
 ///
-/// Stores `tx_id -> tx` mapping
+/// Stores `tx_id -> tx` mapping, and (optionally) a `block_hash -> GCS filter`
+/// mapping so light clients can probabilistically test whether a block
+/// touches one of their addresses without fetching every transaction in it.
 #[derive(Default, Clone)]
 pub struct TransactionService<S: Storage> {
     storage: S,
+    encryption: Encryption,
 }
 
 impl<S> TransactionService<S>
 where
     S: Storage,
 {
-    /// Creates a new instance of transaction service
+    /// Creates a new instance of transaction service which stores
+    /// transactions as plain RLP
     pub fn new(storage: S) -> Self {
-        Self { storage }
+        Self {
+            storage,
+            encryption: Encryption::Plain,
+        }
+    }
+
+    /// Creates a new instance of transaction service which seals every
+    /// stored transaction with ChaCha20-Poly1305, using a key derived from
+    /// `passphrase`
+    pub fn new_encrypted(storage: S, passphrase: &[u8]) -> Self {
+        let mut key_bytes = [0u8; KEY_LEN];
+        let iterations =
+            NonZeroU32::new(PBKDF2_ITERATIONS).expect("PBKDF2_ITERATIONS is non-zero");
+        pbkdf2::derive(
+            pbkdf2::PBKDF2_HMAC_SHA256,
+            iterations,
+            KDF_SALT,
+            passphrase,
+            &mut key_bytes,
+        );
+        let key = LessSafeKey::new(
+            UnboundKey::new(&CHACHA20_POLY1305, &key_bytes)
+                .expect("derived key is the right length for ChaCha20-Poly1305"),
+        );
+
+        Self {
+            storage,
+            encryption: Encryption::Encrypted(Arc::new(key)),
+        }
     }
 
     /// Retrieves transaction with given id
@@ -29,22 +91,382 @@ where
 
         match bytes {
             None => Ok(None),
-            Some(bytes) => Ok(Some(
-                decode(&bytes).context(ErrorKind::DeserializationError)?,
-            )),
+            Some(bytes) => {
+                let plaintext = match &self.encryption {
+                    Encryption::Plain => bytes,
+                    Encryption::Encrypted(key) => open(key, id, bytes)?,
+                };
+
+                Ok(Some(
+                    decode(&plaintext).context(ErrorKind::DeserializationError)?,
+                ))
+            }
         }
     }
 
     /// Sets transaction with given id and value
     pub fn set(&self, id: &TxId, transaction: &Tx) -> Result<()> {
-        self.storage.set(KEYSPACE, id, encode(transaction))?;
+        let plaintext = encode(transaction);
+
+        let bytes = match &self.encryption {
+            Encryption::Plain => plaintext,
+            Encryption::Encrypted(key) => seal(key, id, plaintext)?,
+        };
+
+        self.storage.set(KEYSPACE, id, bytes)?;
+
+        Ok(())
+    }
+
+    /// Builds a BIP158-style compact filter for `items` (the addresses /
+    /// scriptlike outputs a block touches) and stores it against
+    /// `block_hash`, so `filter_may_contain` can later test membership
+    /// without needing the full set of transactions again.
+    pub fn build_filter(&self, block_hash: &[u8; 32], items: &[Vec<u8>]) -> Result<()> {
+        let filter = GcsFilter::build(block_hash, items);
+        self.storage
+            .set(FILTER_KEYSPACE, block_hash, filter.into_bytes())?;
 
         Ok(())
     }
 
+    /// Retrieves the raw, serialized compact filter for a block, if one was built
+    pub fn get_filter(&self, block_hash: &[u8; 32]) -> Result<Option<Vec<u8>>> {
+        self.storage.get(FILTER_KEYSPACE, block_hash)
+    }
+
+    /// Tests whether the filter built for `block_hash` probably contains
+    /// `item`. Because the filter is probabilistic, a `true` result can be a
+    /// false positive, but a `false` result is never a false negative: if
+    /// the block really touched `item`, this always returns `true`.
+    pub fn filter_may_contain(&self, block_hash: &[u8; 32], item: &[u8]) -> Result<bool> {
+        match self.get_filter(block_hash)? {
+            None => Ok(false),
+            Some(bytes) => Ok(GcsFilter::from_bytes(bytes).may_contain(block_hash, item)),
+        }
+    }
+
     /// Clears all storage
     pub fn clear(&self) -> Result<()> {
-        self.storage.clear(KEYSPACE)
+        self.storage.clear(KEYSPACE)?;
+        self.storage.clear(FILTER_KEYSPACE)
+    }
+}
+
+/// seals `plaintext` with a fresh random nonce, authenticating `id` as
+/// associated data (so a ciphertext can't be moved to a different key), and
+/// returns `nonce || ciphertext || tag`
+///
+/// Unlike `open`, failures here aren't data-dependent -- filling a nonce from
+/// the system RNG and sealing a bounded plaintext with an already-validated
+/// key are only expected to fail if the platform itself is broken -- so they
+/// `expect()` rather than surfacing a dedicated `ErrorKind`.
+fn seal(key: &LessSafeKey, id: &TxId, mut plaintext: Vec<u8>) -> Result<Vec<u8>> {
+    let mut nonce_bytes = [0u8; NONCE_LEN];
+    SystemRandom::new()
+        .fill(&mut nonce_bytes)
+        .expect("system randomness source is available");
+    let nonce = Nonce::assume_unique_for_key(nonce_bytes);
+
+    key.seal_in_place_append_tag(nonce, Aad::from(id.as_bytes()), &mut plaintext)
+        .expect("sealing a plaintext within ring's size limit cannot fail");
+
+    let mut sealed = nonce_bytes.to_vec();
+    sealed.extend(plaintext);
+    Ok(sealed)
+}
+
+/// splits `nonce || ciphertext || tag`, then authenticates and decrypts it;
+/// a tampered ciphertext or wrong key surfaces as `ErrorKind::DecryptionError`
+/// instead of silently decoding garbage
+fn open(key: &LessSafeKey, id: &TxId, sealed: Vec<u8>) -> Result<Vec<u8>> {
+    if sealed.len() < NONCE_LEN {
+        return Err(Error::from(ErrorKind::DecryptionError));
+    }
+    let (nonce_bytes, ciphertext) = sealed.split_at(NONCE_LEN);
+
+    let mut nonce_array = [0u8; NONCE_LEN];
+    nonce_array.copy_from_slice(nonce_bytes);
+    let nonce = Nonce::assume_unique_for_key(nonce_array);
+
+    let mut ciphertext = ciphertext.to_vec();
+    let plaintext = key
+        .open_in_place(nonce, Aad::from(id.as_bytes()), &mut ciphertext)
+        .map_err(|_| Error::from(ErrorKind::DecryptionError))?;
+    Ok(plaintext.to_vec())
+}
+
+/// A minimal BIP158-style Golomb-coded set: a probabilistic filter over a
+/// per-block item set, parameterized the same way as Bitcoin's basic filter
+/// (`P = 19`, `M = 784931`).
+mod gcs {
+    // P and M match BIP158's "basic filter" parameters: P is the number of
+    // low bits kept uncompressed per Golomb-Rice-coded delta, and M sets the
+    // false-positive rate to roughly 1/M.
+    const P: u8 = 19;
+    const M: u64 = 784_931;
+
+    pub struct GcsFilter {
+        n: u64,
+        encoded: Vec<u8>,
+    }
+
+    impl GcsFilter {
+        /// builds a filter over `items`, deriving the SipHash key from `block_hash`
+        pub fn build(block_hash: &[u8; 32], items: &[Vec<u8>]) -> Self {
+            let (k0, k1) = derive_siphash_key(block_hash);
+            let n = items.len() as u64;
+            let range = n * M;
+
+            let mut values: Vec<u64> = items
+                .iter()
+                .map(|item| hash_to_range(k0, k1, item, range))
+                .collect();
+            values.sort_unstable();
+
+            let mut writer = BitWriter::new();
+            let mut previous = 0u64;
+            for value in values {
+                golomb_rice_encode(&mut writer, value - previous, P);
+                previous = value;
+            }
+
+            GcsFilter {
+                n,
+                encoded: writer.into_bytes(),
+            }
+        }
+
+        /// `N` (as a varint) followed by the Golomb-Rice coded, bit-packed deltas
+        pub fn into_bytes(self) -> Vec<u8> {
+            let mut out = encode_varint(self.n);
+            out.extend(self.encoded);
+            out
+        }
+
+        pub fn from_bytes(mut bytes: Vec<u8>) -> Self {
+            let (n, consumed) = decode_varint(&bytes);
+            let encoded = bytes.split_off(consumed);
+            GcsFilter { n, encoded }
+        }
+
+        /// probabilistic membership test: false positives are possible
+        /// (roughly 1-in-`M`), false negatives are not.
+        pub fn may_contain(&self, block_hash: &[u8; 32], item: &[u8]) -> bool {
+            let (k0, k1) = derive_siphash_key(block_hash);
+            let range = self.n * M;
+            let target = hash_to_range(k0, k1, item, range);
+
+            let mut reader = BitReader::new(&self.encoded);
+            let mut current = 0u64;
+            for _ in 0..self.n {
+                let delta = match golomb_rice_decode(&mut reader, P) {
+                    Some(delta) => delta,
+                    None => return false,
+                };
+                current += delta;
+                if current == target {
+                    return true;
+                }
+                if current > target {
+                    return false;
+                }
+            }
+            false
+        }
+    }
+
+    /// `block_hash` being a fixed-size array (rather than an arbitrary
+    /// `&[u8]`) is what rules out a short-input panic here: the compiler
+    /// guarantees at least 16 bytes are available.
+    fn derive_siphash_key(block_hash: &[u8; 32]) -> (u64, u64) {
+        let mut k0_bytes = [0u8; 8];
+        let mut k1_bytes = [0u8; 8];
+        k0_bytes.copy_from_slice(&block_hash[0..8]);
+        k1_bytes.copy_from_slice(&block_hash[8..16]);
+        (u64::from_le_bytes(k0_bytes), u64::from_le_bytes(k1_bytes))
+    }
+
+    fn hash_to_range(k0: u64, k1: u64, item: &[u8], range: u64) -> u64 {
+        let hash = siphash24(k0, k1, item);
+        ((u128::from(hash) * u128::from(range)) >> 64) as u64
+    }
+
+    /// SipHash-2-4 (Aumasson & Bernstein), used as in BIP158 to map each
+    /// item into `[0, range)`
+    fn siphash24(k0: u64, k1: u64, data: &[u8]) -> u64 {
+        let mut v0 = k0 ^ 0x736f_6d65_7073_6575;
+        let mut v1 = k1 ^ 0x646f_7261_6e64_6f6d;
+        let mut v2 = k0 ^ 0x6c79_6765_6e65_7261;
+        let mut v3 = k1 ^ 0x7465_6462_7974_6573;
+
+        macro_rules! round {
+            () => {
+                v0 = v0.wrapping_add(v1);
+                v1 = v1.rotate_left(13);
+                v1 ^= v0;
+                v0 = v0.rotate_left(32);
+                v2 = v2.wrapping_add(v3);
+                v3 = v3.rotate_left(16);
+                v3 ^= v2;
+                v0 = v0.wrapping_add(v3);
+                v3 = v3.rotate_left(21);
+                v3 ^= v0;
+                v2 = v2.wrapping_add(v1);
+                v1 = v1.rotate_left(17);
+                v1 ^= v2;
+                v2 = v2.rotate_left(32);
+            };
+        }
+
+        let chunks = data.chunks_exact(8);
+        let remainder = chunks.remainder();
+        for chunk in chunks {
+            let mut buf = [0u8; 8];
+            buf.copy_from_slice(chunk);
+            let m = u64::from_le_bytes(buf);
+            v3 ^= m;
+            round!();
+            round!();
+            v0 ^= m;
+        }
+
+        let mut last_block = [0u8; 8];
+        last_block[..remainder.len()].copy_from_slice(remainder);
+        last_block[7] = data.len() as u8;
+        let m = u64::from_le_bytes(last_block);
+        v3 ^= m;
+        round!();
+        round!();
+        v0 ^= m;
+
+        v2 ^= 0xff;
+        round!();
+        round!();
+        round!();
+        round!();
+
+        v0 ^ v1 ^ v2 ^ v3
+    }
+
+    struct BitWriter {
+        bytes: Vec<u8>,
+        current: u8,
+        filled: u8,
+    }
+
+    impl BitWriter {
+        fn new() -> Self {
+            BitWriter {
+                bytes: Vec::new(),
+                current: 0,
+                filled: 0,
+            }
+        }
+
+        fn push_bit(&mut self, bit: bool) {
+            self.current <<= 1;
+            if bit {
+                self.current |= 1;
+            }
+            self.filled += 1;
+            if self.filled == 8 {
+                self.bytes.push(self.current);
+                self.current = 0;
+                self.filled = 0;
+            }
+        }
+
+        fn into_bytes(mut self) -> Vec<u8> {
+            if self.filled > 0 {
+                self.current <<= 8 - self.filled;
+                self.bytes.push(self.current);
+            }
+            self.bytes
+        }
+    }
+
+    struct BitReader<'a> {
+        bytes: &'a [u8],
+        byte_index: usize,
+        bit_index: u8,
+    }
+
+    impl<'a> BitReader<'a> {
+        fn new(bytes: &'a [u8]) -> Self {
+            BitReader {
+                bytes,
+                byte_index: 0,
+                bit_index: 0,
+            }
+        }
+
+        fn read_bit(&mut self) -> Option<bool> {
+            let byte = *self.bytes.get(self.byte_index)?;
+            let bit = (byte >> (7 - self.bit_index)) & 1 == 1;
+            self.bit_index += 1;
+            if self.bit_index == 8 {
+                self.bit_index = 0;
+                self.byte_index += 1;
+            }
+            Some(bit)
+        }
+    }
+
+    /// unary quotient (`delta >> p` ones terminated by a zero) followed by
+    /// the `p` low bits of `delta`
+    fn golomb_rice_encode(writer: &mut BitWriter, delta: u64, p: u8) {
+        let quotient = delta >> p;
+        for _ in 0..quotient {
+            writer.push_bit(true);
+        }
+        writer.push_bit(false);
+        for i in (0..p).rev() {
+            writer.push_bit((delta >> i) & 1 == 1);
+        }
+    }
+
+    fn golomb_rice_decode(reader: &mut BitReader, p: u8) -> Option<u64> {
+        let mut quotient = 0u64;
+        while reader.read_bit()? {
+            quotient += 1;
+        }
+        let mut remainder = 0u64;
+        for _ in 0..p {
+            remainder = (remainder << 1) | u64::from(reader.read_bit()?);
+        }
+        Some((quotient << p) | remainder)
+    }
+
+    fn encode_varint(mut n: u64) -> Vec<u8> {
+        let mut out = Vec::new();
+        loop {
+            let mut byte = (n & 0x7f) as u8;
+            n >>= 7;
+            if n != 0 {
+                byte |= 0x80;
+            }
+            out.push(byte);
+            if n == 0 {
+                break;
+            }
+        }
+        out
+    }
+
+    fn decode_varint(bytes: &[u8]) -> (u64, usize) {
+        let mut n = 0u64;
+        let mut shift = 0;
+        let mut consumed = 0;
+        for &byte in bytes {
+            consumed += 1;
+            n |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                break;
+            }
+            shift += 7;
+        }
+        (n, consumed)
     }
 }
 
@@ -66,4 +488,56 @@ mod tests {
         assert!(transaction_service.clear().is_ok());
         assert_eq!(None, transaction_service.get(&id).unwrap());
     }
+
+    #[test]
+    fn check_filter_flow() {
+        let transaction_service = TransactionService::new(MemoryStorage::default());
+        let block_hash = [0x11; 32];
+        let addresses = vec![vec![0xaa; 20], vec![0xbb; 20], vec![0xcc; 20]];
+
+        assert_eq!(None, transaction_service.get_filter(&block_hash).unwrap());
+        assert!(transaction_service
+            .build_filter(&block_hash, &addresses)
+            .is_ok());
+        assert!(transaction_service.get_filter(&block_hash).unwrap().is_some());
+
+        for address in &addresses {
+            assert!(transaction_service
+                .filter_may_contain(&block_hash, address)
+                .unwrap());
+        }
+        // not a false negative check (the filter is probabilistic and may
+        // occasionally report a false positive for unrelated items), just
+        // confirms the query path works against a never-inserted block
+        assert!(!transaction_service
+            .filter_may_contain(&[0x22; 32], &addresses[0])
+            .unwrap());
+    }
+
+    #[test]
+    fn check_encrypted_flow() {
+        let transaction_service =
+            TransactionService::new_encrypted(MemoryStorage::default(), b"correct horse battery staple");
+        let id = TxId::zero();
+        let transaction = Tx::default();
+
+        assert!(transaction_service.set(&id, &transaction).is_ok());
+        assert_eq!(transaction, transaction_service.get(&id).unwrap().unwrap());
+    }
+
+    #[test]
+    fn tampered_ciphertext_should_fail_to_decrypt() {
+        let storage = MemoryStorage::default();
+        let transaction_service = TransactionService::new_encrypted(storage.clone(), b"hunter2");
+        let id = TxId::zero();
+
+        transaction_service.set(&id, &Tx::default()).unwrap();
+
+        let mut sealed = storage.get(KEYSPACE, &id).unwrap().unwrap();
+        let last = sealed.len() - 1;
+        sealed[last] ^= 0xff;
+        storage.set(KEYSPACE, &id, sealed).unwrap();
+
+        assert!(transaction_service.get(&id).is_err());
+    }
 }